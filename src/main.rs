@@ -1,22 +1,32 @@
 use axum::{
     extract::{ContentLengthLimit, Multipart},
     handler::{get},
-    response::Html,
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
     Router,
 };
 use image::{
-    io::Reader as ImageReader, EncodableLayout, ImageError, Rgba,
+    imageops::{self, FilterType},
+    io::Reader as ImageReader,
+    DynamicImage, EncodableLayout, ImageError, ImageFormat, Rgba, RgbaImage,
 };
 use imageproc::drawing::draw_text_mut;
 use rusttype::{Font, Scale};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    fs,
     fs::File,
     io::Error,
     io::{Cursor, Read},
     net::SocketAddr,
-    path::Path,
+    path::{Path, PathBuf},
 };
+use tower_http::trace::TraceLayer;
+
+/// Directory rendered outputs are cached under, keyed by a hash of the
+/// input image(s) plus the operation's parameters.
+const CACHE_DIR: &str = "cache";
 
 #[derive(Deserialize, Debug)]
 struct WatermarkForm {
@@ -26,6 +36,68 @@ struct WatermarkForm {
     posy: u32,
 }
 
+/// Errors a handler can fail with, each carrying enough detail to render a
+/// meaningful HTTP response instead of panicking the request task.
+#[derive(Debug)]
+enum ProcessorError {
+    /// The multipart body (or one of its fields) could not be read, e.g.
+    /// because it exceeded the configured content-length limit.
+    PayloadOverflow,
+    /// A required form field was not present in the multipart upload.
+    MissingField(&'static str),
+    /// A numeric field's text could not be parsed, e.g. `scale=abc`.
+    InvalidNumber(String),
+    /// A field's text was well-formed but not one of the accepted values,
+    /// or a combination of fields didn't satisfy a required constraint.
+    InvalidValue(String),
+    /// The uploaded bytes are not a supported image format.
+    UnsupportedImageFormat,
+    /// The image crate recognized the format but failed to decode it.
+    DecodeFailed(ImageError),
+    /// The image crate failed to encode the rendered output, e.g. a
+    /// requested output format has no encoder in this build.
+    EncodeFailed(ImageError),
+    /// The bundled watermark font failed to load.
+    FontLoad,
+}
+
+impl From<ImageError> for ProcessorError {
+    fn from(err: ImageError) -> Self {
+        match err {
+            ImageError::Unsupported(_) => ProcessorError::UnsupportedImageFormat,
+            other => ProcessorError::DecodeFailed(other),
+        }
+    }
+}
+
+impl IntoResponse for ProcessorError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            ProcessorError::PayloadOverflow => StatusCode::PAYLOAD_TOO_LARGE,
+            ProcessorError::MissingField(_)
+            | ProcessorError::InvalidNumber(_)
+            | ProcessorError::InvalidValue(_) => StatusCode::BAD_REQUEST,
+            ProcessorError::UnsupportedImageFormat => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ProcessorError::DecodeFailed(_)
+            | ProcessorError::EncodeFailed(_)
+            | ProcessorError::FontLoad => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let message = match self {
+            ProcessorError::PayloadOverflow => "uploaded file is too large".to_string(),
+            ProcessorError::MissingField(name) => format!("missing required field `{}`", name),
+            ProcessorError::InvalidNumber(field) => {
+                format!("invalid number for field `{}`", field)
+            }
+            ProcessorError::InvalidValue(reason) => reason,
+            ProcessorError::UnsupportedImageFormat => "image type is not supported".to_string(),
+            ProcessorError::DecodeFailed(err) => format!("failed to decode image: {}", err),
+            ProcessorError::EncodeFailed(err) => format!("failed to encode output image: {}", err),
+            ProcessorError::FontLoad => "failed to load watermark font".to_string(),
+        };
+        (status, Html(format!("<h1>{}</h1>", message))).into_response()
+    }
+}
+
 #[tokio::main]
 async fn main() {
     
@@ -36,9 +108,12 @@ async fn main() {
 
     let app = Router::new()
         .route("/", get(hello_world))
-        .route("/img-watermark", get(show_form).post(watermark_handler));
+        .route("/img-watermark", get(show_form).post(watermark_handler))
+        .route("/img-resize", get(show_resize_form).post(resize_handler))
+        .route("/img-ascii", get(show_ascii_form).post(ascii_handler))
+        .layer(TraceLayer::new_for_http());
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
+    let addr = bind_addr();
     println!("listening on {}", addr);
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
@@ -46,6 +121,15 @@ async fn main() {
         .unwrap();
 }
 
+/// Parses the listen address from `argv[1]`, defaulting to `0.0.0.0:8080`
+/// when no argument (or an unparsable one) is given.
+fn bind_addr() -> SocketAddr {
+    std::env::args()
+        .nth(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 8080)))
+}
+
 async fn show_form() -> Html<&'static str> {
     Html(
         r#"
@@ -105,6 +189,36 @@ async fn show_form() -> Html<&'static str> {
                             <input type="text" name="text"/>
                         </label>
                     </div>
+                    <div>
+                        <label>
+                            mode:
+                            <select name="mode">
+                                <option value="text">text</option>
+                                <option value="logo">logo</option>
+                            </select>
+                        </label>
+                    </div>
+                    <div>
+                        <label>
+                            logo overlay (used when mode = logo):
+                            <input type="file" name="overlay"/>
+                            opacity (0-255):
+                            <input type="number" name="opacity" min="0" max="255"/>
+                            logo scale factor:
+                            <input type="number" name="logo_scale" step="0.1"/>
+                        </label>
+                    </div>
+                    <div>
+                        <label>
+                            output format (defaults to the uploaded image's format):
+                            <select name="format">
+                                <option value="">(auto)</option>
+                                <option value="png">PNG</option>
+                                <option value="jpeg">JPEG</option>
+                                <option value="gif">GIF</option>
+                            </select>
+                        </label>
+                    </div>
                     <button type="submit">Submit</button>
                 </form>
             </body>
@@ -113,70 +227,238 @@ async fn show_form() -> Html<&'static str> {
     )
 }
 
-async fn hello_world() -> Html<&'static str> {
-    Html("<h1>Hello, World!</h1>")
+async fn show_resize_form() -> Html<&'static str> {
+    Html(
+        r#"
+        <!doctype html>
+        <style>
+            form {
+                padding: 1em;
+            }
+            input {
+                display:block;
+                margin: 5px;
+            }
+            div {
+                padding: 5px;
+                border: 1px solid #eee;
+                width: 250px;
+            }
+            button {
+                width: 250px;
+            }
+        </style>
+        <html>
+            <head><title>image processor</title></head>
+            <body>
+                <form action="/img-resize" method="post" enctype="multipart/form-data">
+                    <h3>Upload file to resize</h3>
+                    <div>
+                        <label>
+                            Upload file:
+                            <input type="file" name="file" multiple>
+                        </label>
+                    </div>
+                    <div>
+                        <label>
+                            width:
+                            <input type="number" name="width"/>
+                            height:
+                            <input type="number" name="height"/>
+                        </label>
+                    </div>
+                    <div>
+                        <label>
+                            filter:
+                            <select name="filter">
+                                <option value="nearest">Nearest</option>
+                                <option value="triangle">Triangle</option>
+                                <option value="catmullrom">CatmullRom</option>
+                                <option value="gaussian">Gaussian</option>
+                                <option value="lanczos3">Lanczos3</option>
+                            </select>
+                        </label>
+                    </div>
+                    <button type="submit">Submit</button>
+                </form>
+            </body>
+        </html>
+        "#,
+    )
 }
 
-async fn error_page(err_msg: String) -> Html<String> {
-    Html(err_msg)
+async fn show_ascii_form() -> Html<&'static str> {
+    Html(
+        r#"
+        <!doctype html>
+        <style>
+            form {
+                padding: 1em;
+            }
+            input {
+                display:block;
+                margin: 5px;
+            }
+            div {
+                padding: 5px;
+                border: 1px solid #eee;
+                width: 250px;
+            }
+            button {
+                width: 250px;
+            }
+        </style>
+        <html>
+            <head><title>image processor</title></head>
+            <body>
+                <form action="/img-ascii" method="post" enctype="multipart/form-data">
+                    <h3>Upload file to convert to ASCII art</h3>
+                    <div>
+                        <label>
+                            Upload file:
+                            <input type="file" name="file" multiple>
+                        </label>
+                    </div>
+                    <div>
+                        <label>
+                            character width:
+                            <input type="number" name="width" value="80"/>
+                        </label>
+                    </div>
+                    <div>
+                        <label>
+                            <input type="checkbox" name="deep"/> use deep ramp
+                        </label>
+                        <label>
+                            <input type="checkbox" name="invert"/> invert ramp
+                        </label>
+                        <label>
+                            <input type="checkbox" name="color"/> ANSI color
+                        </label>
+                    </div>
+                    <button type="submit">Submit</button>
+                </form>
+            </body>
+        </html>
+        "#,
+    )
+}
+
+async fn hello_world() -> Html<&'static str> {
+    Html("<h1>Hello, World!</h1>")
 }
 
 // accept 250mb file size
 async fn watermark_handler(
+    headers: HeaderMap,
     ContentLengthLimit(mut multipart): ContentLengthLimit<Multipart, { 250 * 1024 * 1024 }>,
-) -> Html<String> {
+) -> Result<Html<String>, ProcessorError> {
     let mut bytes: Vec<u8> = Vec::new();
+    let mut overlay_bytes: Vec<u8> = Vec::new();
     let mut scale_num = 18.0;
     let mut posx: u32 = 0;
     let mut posy: u32 = 0;
     let mut text = "Blue Bird".into();
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap().to_string();
+    let mut mode = "text".to_string();
+    let mut opacity: u8 = 255;
+    let mut logo_scale: f32 = 1.0;
+    let mut format_field: Option<String> = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| ProcessorError::PayloadOverflow)?
+    {
+        let name = field
+            .name()
+            .ok_or(ProcessorError::MissingField("name"))?
+            .to_string();
         println!("name: {}", name);
 
         match &*name {
             "file" => {
-                let data = field.bytes().await.unwrap();
+                let data = field
+                    .bytes()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?;
                 bytes = data.to_vec();
                 println!("Length of `{}` is {} bytes", name, data.len());
             }
+            "overlay" => {
+                let data = field
+                    .bytes()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?;
+                overlay_bytes = data.to_vec();
+                println!("Length of `{}` is {} bytes", name, data.len());
+            }
+            "mode" => {
+                mode = field
+                    .text()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?;
+                println!("mode: {}", mode);
+            }
             "scale" => {
-                scale_num = match field.text().await.unwrap().parse() {
-                    Ok(num) => num,
-                    Err(_err) => {
-                        return error_page("invalid scale number".into()).await;
-                    }
-                };
+                scale_num = field
+                    .text()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?
+                    .parse()
+                    .map_err(|_| ProcessorError::InvalidNumber("scale".into()))?;
                 println!("scale: {}", scale_num);
             }
             "posx" => {
-                posx = match field.text().await.unwrap().parse() {
-                    Ok(num) => num,
-                    Err(_err) => {
-                        return error_page(
-                            "invalid position x number, only positive number".into(),
-                        )
-                        .await;
-                    }
-                };
+                posx = field
+                    .text()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?
+                    .parse()
+                    .map_err(|_| ProcessorError::InvalidNumber("posx".into()))?;
                 println!("posx: {}", posx);
             }
             "posy" => {
-                posy = match field.text().await.unwrap().parse() {
-                    Ok(num) => num,
-                    Err(_err) => {
-                        return error_page(
-                            "invalid position y number, only positive number".into(),
-                        )
-                        .await;
-                    }
-                };
+                posy = field
+                    .text()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?
+                    .parse()
+                    .map_err(|_| ProcessorError::InvalidNumber("posy".into()))?;
                 println!("posy: {}", posy);
             }
             "text" => {
-                text = field.text().await.unwrap().to_string();
+                text = field
+                    .text()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?;
                 println!("text: {}", text);
             }
+            "opacity" => {
+                opacity = field
+                    .text()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?
+                    .parse()
+                    .map_err(|_| ProcessorError::InvalidNumber("opacity".into()))?;
+                println!("opacity: {}", opacity);
+            }
+            "logo_scale" => {
+                logo_scale = field
+                    .text()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?
+                    .parse()
+                    .map_err(|_| ProcessorError::InvalidNumber("logo_scale".into()))?;
+                println!("logo_scale: {}", logo_scale);
+            }
+            "format" => {
+                format_field = Some(
+                    field
+                        .text()
+                        .await
+                        .map_err(|_| ProcessorError::PayloadOverflow)?,
+                );
+                println!("format: {:?}", format_field);
+            }
             _ => println!("processed all form value"),
         }
     }
@@ -185,15 +467,153 @@ async fn watermark_handler(
         y: scale_num,
     };
 
-    let watermarked_img = match draw_watermark_on_image(bytes, scale, &text, posx, posy) {
-        Ok(i) => i,
-        Err(err) => {
-            println!("error when drawing on image, {:?}", err);
-            return Html("<h1>Image type is not supported</h1>".into());
-        }
+    let watermark = match &*mode {
+        "logo" => Watermark::Logo {
+            overlay: overlay_bytes,
+            posx,
+            posy,
+            opacity,
+            scale_factor: logo_scale,
+        },
+        _ => Watermark::Text {
+            text,
+            scale,
+            posx,
+            posy,
+        },
+    };
+
+    let accept_format = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_accept_header);
+    let output_format = format_field
+        .as_deref()
+        .and_then(parse_image_format)
+        .or(accept_format)
+        .or_else(|| determine_image_format(&bytes))
+        .filter(|format| is_encodable_format(*format))
+        .unwrap_or(ImageFormat::Jpeg);
+
+    let cache_params = watermark.cache_params(output_format);
+    let overlay_for_cache = match &watermark {
+        Watermark::Logo { overlay, .. } => Some(overlay.clone()),
+        Watermark::Text { .. } => None,
+    };
+    let cache_images: Vec<&[u8]> = match &overlay_for_cache {
+        Some(overlay) => vec![bytes.as_slice(), overlay.as_slice()],
+        None => vec![bytes.as_slice()],
     };
 
+    let watermarked_img = load_or_render(&cache_images, &cache_params, || {
+        draw_watermark_on_image(bytes.clone(), watermark, output_format)
+    })?;
+
     let base64_img = base64::encode(watermarked_img);
+    let mime = mime_for_format(output_format);
+
+    let html_resp = format!(
+        r#"
+        <!doctype html>
+        <html>
+            <head><title>image processor</title></head>
+            <body>
+                <h3>Output:</h3>
+                <div style="border: 1px solid #eee; width: min-content; padding: 5px;">
+                <img src="data:{};base64, {}"/>
+                </div>
+            </body>
+        </html>
+    "#,
+        mime, base64_img
+    );
+
+    Ok(Html(html_resp))
+}
+
+// accept 250mb file size
+async fn resize_handler(
+    ContentLengthLimit(mut multipart): ContentLengthLimit<Multipart, { 250 * 1024 * 1024 }>,
+) -> Result<Html<String>, ProcessorError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut width: Option<u32> = None;
+    let mut height: Option<u32> = None;
+    let mut filter = FilterType::Lanczos3;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| ProcessorError::PayloadOverflow)?
+    {
+        let name = field
+            .name()
+            .ok_or(ProcessorError::MissingField("name"))?
+            .to_string();
+        println!("name: {}", name);
+
+        match &*name {
+            "file" => {
+                let data = field
+                    .bytes()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?;
+                bytes = data.to_vec();
+                println!("Length of `{}` is {} bytes", name, data.len());
+            }
+            "width" => {
+                width = Some(
+                    field
+                        .text()
+                        .await
+                        .map_err(|_| ProcessorError::PayloadOverflow)?
+                        .parse()
+                        .map_err(|_| ProcessorError::InvalidNumber("width".into()))?,
+                );
+                println!("width: {:?}", width);
+            }
+            "height" => {
+                height = Some(
+                    field
+                        .text()
+                        .await
+                        .map_err(|_| ProcessorError::PayloadOverflow)?
+                        .parse()
+                        .map_err(|_| ProcessorError::InvalidNumber("height".into()))?,
+                );
+                println!("height: {:?}", height);
+            }
+            "filter" => {
+                let filter_name = field
+                    .text()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?;
+                filter = match &*filter_name {
+                    "nearest" => FilterType::Nearest,
+                    "triangle" => FilterType::Triangle,
+                    "catmullrom" => FilterType::CatmullRom,
+                    "gaussian" => FilterType::Gaussian,
+                    "lanczos3" => FilterType::Lanczos3,
+                    _ => {
+                        return Err(ProcessorError::InvalidValue(format!(
+                            "invalid filter `{}`",
+                            filter_name
+                        )));
+                    }
+                };
+                println!("filter: {}", filter_name);
+            }
+            _ => println!("processed all form value"),
+        }
+    }
+
+    if width.is_none() && height.is_none() {
+        return Err(ProcessorError::InvalidValue(
+            "at least one of width or height is required".into(),
+        ));
+    }
+
+    let resized_img = resize_image(bytes, width, height, filter)?;
+
+    let base64_img = base64::encode(resized_img);
 
     let html_resp = format!(
         r#"
@@ -211,7 +631,77 @@ async fn watermark_handler(
         base64_img
     );
 
-    Html(html_resp)
+    Ok(Html(html_resp))
+}
+
+// accept 250mb file size
+async fn ascii_handler(
+    ContentLengthLimit(mut multipart): ContentLengthLimit<Multipart, { 250 * 1024 * 1024 }>,
+) -> Result<Html<String>, ProcessorError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut width: u32 = 80;
+    let mut deep = false;
+    let mut invert = false;
+    let mut color = false;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| ProcessorError::PayloadOverflow)?
+    {
+        let name = field
+            .name()
+            .ok_or(ProcessorError::MissingField("name"))?
+            .to_string();
+        println!("name: {}", name);
+
+        match &*name {
+            "file" => {
+                let data = field
+                    .bytes()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?;
+                bytes = data.to_vec();
+                println!("Length of `{}` is {} bytes", name, data.len());
+            }
+            "width" => {
+                width = field
+                    .text()
+                    .await
+                    .map_err(|_| ProcessorError::PayloadOverflow)?
+                    .parse()
+                    .map_err(|_| ProcessorError::InvalidNumber("width".into()))?;
+                println!("width: {}", width);
+            }
+            "deep" => {
+                deep = true;
+            }
+            "invert" => {
+                invert = true;
+            }
+            "color" => {
+                color = true;
+            }
+            _ => println!("processed all form value"),
+        }
+    }
+
+    let ascii_art = render_ascii_art(bytes, width, deep, invert, color)?;
+
+    let html_resp = format!(
+        r#"
+        <!doctype html>
+        <html>
+            <head><title>image processor</title></head>
+            <body>
+                <h3>Output:</h3>
+                <pre style="background: #111; color: #eee; padding: 1em; overflow: auto;">{}</pre>
+            </body>
+        </html>
+    "#,
+        ascii_art
+    );
+
+    Ok(Html(html_resp))
 }
 
 fn read_image(path: &str) -> Result<Vec<u8>, Error> {
@@ -222,47 +712,352 @@ fn read_image(path: &str) -> Result<Vec<u8>, Error> {
     Ok(buff)
 }
 
-fn determine_image_format(img: Vec<u8>) {
-    let cursor = Cursor::new(img.as_bytes());
-    let reader = ImageReader::new(cursor)
-        .with_guessed_format()
-        .expect("never failed this");
-    println!("format guessed: {:?}", reader.format());
+fn determine_image_format(img: &[u8]) -> Option<ImageFormat> {
+    let cursor = Cursor::new(img);
+    let format = ImageReader::new(cursor).with_guessed_format().ok()?.format();
+    println!("format guessed: {:?}", format);
+    format
 }
 
-fn draw_watermark_on_image(
-    img: Vec<u8>,
-    scale: Scale,
-    text: &str,
+/// Maps a `format` form field value (case-insensitive) to an `ImageFormat`.
+/// WebP is intentionally not offered: this crate's `image` version can only
+/// decode WebP, not encode it.
+fn parse_image_format(name: &str) -> Option<ImageFormat> {
+    match name.to_lowercase().as_str() {
+        "png" => Some(ImageFormat::Png),
+        "gif" => Some(ImageFormat::Gif),
+        "jpeg" | "jpg" => Some(ImageFormat::Jpeg),
+        _ => None,
+    }
+}
+
+/// Picks the first image format this service can emit out of an `Accept`
+/// header's value. WebP is intentionally excluded; see `parse_image_format`.
+fn parse_accept_header(accept: &str) -> Option<ImageFormat> {
+    accept.split(',').find_map(|part| {
+        match part.split(';').next()?.trim() {
+            "image/png" => Some(ImageFormat::Png),
+            "image/gif" => Some(ImageFormat::Gif),
+            "image/jpeg" => Some(ImageFormat::Jpeg),
+            _ => None,
+        }
+    })
+}
+
+/// Returns whether this crate's `image` build can encode `format`. WebP can
+/// only be decoded, not encoded, so it must never be selected as an output
+/// format even when it's the format an uploaded file was detected as.
+fn is_encodable_format(format: ImageFormat) -> bool {
+    !matches!(format, ImageFormat::WebP)
+}
+
+fn mime_for_format(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "image/png",
+        ImageFormat::WebP => "image/webp",
+        ImageFormat::Gif => "image/gif",
+        _ => "image/jpeg",
+    }
+}
+
+/// Selects how `draw_watermark_on_image` marks up the base image: stamped
+/// text, or a second image composited on top as a logo.
+enum Watermark {
+    Text {
+        text: String,
+        scale: Scale,
+        posx: u32,
+        posy: u32,
+    },
+    Logo {
+        overlay: Vec<u8>,
+        posx: u32,
+        posy: u32,
+        /// 0 (fully transparent) to 255 (fully opaque).
+        opacity: u8,
+        /// Multiplier applied to the overlay's original dimensions before compositing.
+        scale_factor: f32,
+    },
+}
+
+/// Canonical, serializable view of a `Watermark`'s parameters, used to key
+/// the render cache. Kept separate from `Watermark` since `rusttype::Scale`
+/// isn't `Serialize`.
+#[derive(Serialize)]
+struct WatermarkCacheParams<'a> {
+    mode: &'a str,
+    text: Option<&'a str>,
+    scale: Option<f32>,
     posx: u32,
     posy: u32,
-) -> Result<Vec<u8>, ImageError> {
-    let cursor = Cursor::new(img.as_bytes());
-    let mut dyna_img = match ImageReader::new(cursor).with_guessed_format()?.decode() {
-        Ok(i) => i,
+    opacity: Option<u8>,
+    logo_scale: Option<f32>,
+    output_format: &'a str,
+}
+
+impl Watermark {
+    fn cache_params(&self, output_format: ImageFormat) -> WatermarkCacheParams {
+        let output_format = mime_for_format(output_format);
+        match self {
+            Watermark::Text {
+                text,
+                scale,
+                posx,
+                posy,
+            } => WatermarkCacheParams {
+                mode: "text",
+                text: Some(text.as_str()),
+                scale: Some(scale.x),
+                posx: *posx,
+                posy: *posy,
+                opacity: None,
+                logo_scale: None,
+                output_format,
+            },
+            Watermark::Logo {
+                posx,
+                posy,
+                opacity,
+                scale_factor,
+                ..
+            } => WatermarkCacheParams {
+                mode: "logo",
+                text: None,
+                scale: None,
+                posx: *posx,
+                posy: *posy,
+                opacity: Some(*opacity),
+                logo_scale: Some(*scale_factor),
+                output_format,
+            },
+        }
+    }
+}
+
+/// Hashes the given input image(s) together with a canonical serialization
+/// of the operation's parameters to form a content-addressed cache key.
+fn compute_cache_key(images: &[&[u8]], params: &impl Serialize) -> String {
+    let mut hasher = Sha256::new();
+    for image in images {
+        hasher.update(image);
+    }
+    let params_json = serde_json::to_vec(params).expect("serialize cache params");
+    hasher.update(&params_json);
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn cache_path(key: &str) -> PathBuf {
+    Path::new(CACHE_DIR).join(key)
+}
+
+/// Reads a cached render for `(images, params)` if present, otherwise calls
+/// `render` and writes its result to the cache before returning it.
+fn load_or_render<E>(
+    images: &[&[u8]],
+    params: &impl Serialize,
+    render: impl FnOnce() -> Result<Vec<u8>, E>,
+) -> Result<Vec<u8>, E> {
+    let key = compute_cache_key(images, params);
+    let path = cache_path(&key);
+
+    if let Ok(cached) = fs::read(&path) {
+        println!("cache hit: {}", key);
+        return Ok(cached);
+    }
+
+    println!("cache miss: {}", key);
+    let rendered = render()?;
+
+    if let Err(err) = fs::create_dir_all(CACHE_DIR).and_then(|_| fs::write(&path, &rendered)) {
+        println!("failed to write cache entry {}: {:?}", key, err);
+    }
+
+    Ok(rendered)
+}
+
+/// Guesses the image's encoding from its bytes and decodes it. Shared by
+/// every handler so format detection stays in one place.
+fn decode_image(img: &[u8]) -> Result<DynamicImage, ImageError> {
+    let cursor = Cursor::new(img);
+    match ImageReader::new(cursor).with_guessed_format()?.decode() {
+        Ok(i) => Ok(i),
         Err(err) => {
             println!("image cannot, {:?}", err);
-            return Err(err);
+            Err(err)
         }
-    };
+    }
+}
 
-    let font_data: &[u8] = include_bytes!("../fonts/Urbanist/static/Urbanist-Black.ttf");
-    let font: Font<'static> = match Font::try_from_bytes(font_data) {
-        Some(f) => f,
-        None => {
-            println!("font error");
-            return Err(ImageError::IoError(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "font error",
-            )));
+/// Resolves the output dimensions for a resize request. When only one of
+/// `width`/`height` is given, the other is derived from `source_width` /
+/// `source_height` to preserve the original aspect ratio.
+fn compute_target_dimensions(
+    source_width: u32,
+    source_height: u32,
+    width: Option<u32>,
+    height: Option<u32>,
+) -> (u32, u32) {
+    match (width, height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => {
+            let h = (w as f32 * source_height as f32 / source_width as f32).round() as u32;
+            (w, h.max(1))
+        }
+        (None, Some(h)) => {
+            let w = (h as f32 * source_width as f32 / source_height as f32).round() as u32;
+            (w.max(1), h)
         }
+        (None, None) => (source_width, source_height),
+    }
+}
+
+fn resize_image(
+    img: Vec<u8>,
+    width: Option<u32>,
+    height: Option<u32>,
+    filter: FilterType,
+) -> Result<Vec<u8>, ProcessorError> {
+    let dyna_img = decode_image(img.as_bytes())?;
+
+    let (target_width, target_height) =
+        compute_target_dimensions(dyna_img.width(), dyna_img.height(), width, height);
+
+    let resized = dyna_img.resize_exact(target_width, target_height, filter);
+
+    let mut out_img = Vec::new();
+    resized
+        .write_to(&mut out_img, image::ImageFormat::Jpeg)
+        .map_err(ProcessorError::EncodeFailed)?;
+
+    Ok(out_img)
+}
+
+const ASCII_RAMP: &str = " .:-=+*#%@";
+const ASCII_RAMP_DEEP: &str =
+    " .'`^\",:;Il!i><~+_-?][}{1)(|\\/tfjrxnuvczXYUJCLQ0OZmwqpdbkhao*#MW&8%B@$";
+
+/// Maps a 0-255 luminance value to a character from `ramp`, brightest end last
+/// unless `invert` flips the mapping.
+fn luminance_to_char(luminance: u8, ramp: &[char], invert: bool) -> char {
+    let level = if invert { 255 - luminance } else { luminance };
+    let index = (level as usize * (ramp.len() - 1)) / 255;
+    ramp[index]
+}
+
+/// Appends `ch` to `out`, escaping the characters that are special to HTML.
+/// Several ramp characters (`<`, `>`, `&`, `"`) would otherwise be read by
+/// the browser as markup when the art is embedded in a `<pre>` block.
+fn push_escaped_html_char(out: &mut String, ch: char) {
+    match ch {
+        '&' => out.push_str("&amp;"),
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        '"' => out.push_str("&quot;"),
+        '\'' => out.push_str("&#39;"),
+        other => out.push(other),
+    }
+}
+
+/// Renders an uploaded image as ASCII (or ANSI-colored ASCII) text, halving
+/// the row count relative to `width` to compensate for character aspect ratio.
+fn render_ascii_art(
+    img: Vec<u8>,
+    width: u32,
+    deep: bool,
+    invert: bool,
+    color: bool,
+) -> Result<String, ProcessorError> {
+    let dyna_img = decode_image(img.as_bytes())?;
+    let rgba = dyna_img.to_rgba8();
+
+    let target_width = width.max(1);
+    let target_height = ((rgba.height() as f32 * target_width as f32 / rgba.width() as f32)
+        / 2.0)
+        .round()
+        .max(1.0) as u32;
+    let resized = imageops::resize(&rgba, target_width, target_height, FilterType::Triangle);
+
+    let ramp: Vec<char> = if deep {
+        ASCII_RAMP_DEEP.chars().collect()
+    } else {
+        ASCII_RAMP.chars().collect()
     };
-    // let scale: Scale = Scale { x: 18.0, y: 18.0 };
-    // let text = "IBM Technology Garage";
-    let color = Rgba([0u8, 0u8, 0u8, 0u8]);
-    // let x = dyna_img.width() - posx as u32;
-    // let y = dyna_img.height() - posy as u32;
-    draw_text_mut(&mut dyna_img, color, posx, posy, scale, &font, text);
+
+    let mut art = String::new();
+    for y in 0..resized.height() {
+        for x in 0..resized.width() {
+            let [r, g, b, _a] = resized.get_pixel(x, y).0;
+            let luminance = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+            let ch = luminance_to_char(luminance as u8, &ramp, invert);
+            if color {
+                art.push_str(&format!("<span style=\"color: rgb({}, {}, {})\">", r, g, b));
+                push_escaped_html_char(&mut art, ch);
+                art.push_str("</span>");
+            } else {
+                push_escaped_html_char(&mut art, ch);
+            }
+        }
+        art.push('\n');
+    }
+
+    Ok(art)
+}
+
+fn draw_watermark_on_image(
+    img: Vec<u8>,
+    watermark: Watermark,
+    output_format: ImageFormat,
+) -> Result<Vec<u8>, ProcessorError> {
+    let mut dyna_img = decode_image(img.as_bytes())?;
+
+    match watermark {
+        Watermark::Text {
+            text,
+            scale,
+            posx,
+            posy,
+        } => {
+            let font_data: &[u8] = include_bytes!("../fonts/Urbanist/static/Urbanist-Black.ttf");
+            let font: Font<'static> =
+                Font::try_from_bytes(font_data).ok_or(ProcessorError::FontLoad)?;
+            let color = Rgba([0u8, 0u8, 0u8, 0u8]);
+            draw_text_mut(&mut dyna_img, color, posx, posy, scale, &font, &text);
+        }
+        Watermark::Logo {
+            overlay,
+            posx,
+            posy,
+            opacity,
+            scale_factor,
+        } => {
+            let overlay_img = decode_image(overlay.as_bytes())?;
+            let mut overlay_rgba: RgbaImage = overlay_img.into_rgba8();
+
+            if (scale_factor - 1.0).abs() > f32::EPSILON {
+                let new_width = ((overlay_rgba.width() as f32) * scale_factor).round() as u32;
+                let new_height = ((overlay_rgba.height() as f32) * scale_factor).round() as u32;
+                overlay_rgba = imageops::resize(
+                    &overlay_rgba,
+                    new_width.max(1),
+                    new_height.max(1),
+                    FilterType::Lanczos3,
+                );
+            }
+
+            if opacity < 255 {
+                for pixel in overlay_rgba.pixels_mut() {
+                    let alpha = pixel.0[3] as u16 * opacity as u16 / 255;
+                    pixel.0[3] = alpha as u8;
+                }
+            }
+
+            imageops::overlay(&mut dyna_img, &overlay_rgba, posx as i64, posy as i64);
+        }
+    }
 
     // save to local
     // dyna_img.save("images/kubernetes-watermarked.jpg").unwrap();
@@ -270,8 +1065,141 @@ fn draw_watermark_on_image(
     // save in memory
     let mut out_img = Vec::new();
     dyna_img
-        .write_to(&mut out_img, image::ImageFormat::Jpeg)
-        .expect("writing to memory failed");
+        .write_to(&mut out_img, output_format)
+        .map_err(ProcessorError::EncodeFailed)?;
 
     Ok(out_img)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn luminance_to_char_maps_black_to_first_rung() {
+        let ramp = ['a', 'b', 'c'];
+        assert_eq!(luminance_to_char(0, &ramp, false), 'a');
+    }
+
+    #[test]
+    fn luminance_to_char_maps_white_to_last_rung() {
+        let ramp = ['a', 'b', 'c'];
+        assert_eq!(luminance_to_char(255, &ramp, false), 'c');
+    }
+
+    #[test]
+    fn luminance_to_char_invert_flips_the_mapping() {
+        let ramp = ['a', 'b', 'c'];
+        assert_eq!(luminance_to_char(0, &ramp, true), 'c');
+        assert_eq!(luminance_to_char(255, &ramp, true), 'a');
+    }
+
+    #[test]
+    fn push_escaped_html_char_escapes_special_characters() {
+        let mut out = String::new();
+        for ch in ['<', '>', '&', '"', '\'', 'x'] {
+            push_escaped_html_char(&mut out, ch);
+        }
+        assert_eq!(out, "&lt;&gt;&amp;&quot;&#39;x");
+    }
+
+    #[test]
+    fn compute_cache_key_is_deterministic() {
+        let params = WatermarkCacheParams {
+            mode: "text",
+            text: Some("hello"),
+            scale: Some(18.0),
+            posx: 0,
+            posy: 0,
+            opacity: None,
+            logo_scale: None,
+            output_format: "image/jpeg",
+        };
+        let key_a = compute_cache_key(&[b"same bytes"], &params);
+        let key_b = compute_cache_key(&[b"same bytes"], &params);
+        assert_eq!(key_a, key_b);
+    }
+
+    #[test]
+    fn compute_cache_key_changes_with_input_bytes() {
+        let params = WatermarkCacheParams {
+            mode: "text",
+            text: Some("hello"),
+            scale: Some(18.0),
+            posx: 0,
+            posy: 0,
+            opacity: None,
+            logo_scale: None,
+            output_format: "image/jpeg",
+        };
+        let key_a = compute_cache_key(&[b"image a"], &params);
+        let key_b = compute_cache_key(&[b"image b"], &params);
+        assert_ne!(key_a, key_b);
+    }
+
+    #[test]
+    fn compute_cache_key_changes_with_params() {
+        let base = WatermarkCacheParams {
+            mode: "text",
+            text: Some("hello"),
+            scale: Some(18.0),
+            posx: 0,
+            posy: 0,
+            opacity: None,
+            logo_scale: None,
+            output_format: "image/jpeg",
+        };
+        let moved = WatermarkCacheParams {
+            posx: 10,
+            ..base
+        };
+        let key_a = compute_cache_key(&[b"same bytes"], &base);
+        let key_b = compute_cache_key(&[b"same bytes"], &moved);
+        assert_ne!(key_a, key_b);
+    }
+
+    #[test]
+    fn compute_cache_key_changes_with_extra_image() {
+        let params = WatermarkCacheParams {
+            mode: "logo",
+            text: None,
+            scale: None,
+            posx: 0,
+            posy: 0,
+            opacity: Some(255),
+            logo_scale: Some(1.0),
+            output_format: "image/jpeg",
+        };
+        let key_without_overlay = compute_cache_key(&[b"base"], &params);
+        let key_with_overlay = compute_cache_key(&[b"base", b"overlay"], &params);
+        assert_ne!(key_without_overlay, key_with_overlay);
+    }
+
+    #[test]
+    fn compute_target_dimensions_uses_both_when_given() {
+        assert_eq!(compute_target_dimensions(400, 200, Some(50), Some(50)), (50, 50));
+    }
+
+    #[test]
+    fn compute_target_dimensions_derives_height_from_width() {
+        // 400x200 source, asked for width 100 -> height should halve to 50.
+        assert_eq!(compute_target_dimensions(400, 200, Some(100), None), (100, 50));
+    }
+
+    #[test]
+    fn compute_target_dimensions_derives_width_from_height() {
+        // 400x200 source, asked for height 50 -> width should double to 100.
+        assert_eq!(compute_target_dimensions(400, 200, None, Some(50)), (100, 50));
+    }
+
+    #[test]
+    fn compute_target_dimensions_clamps_derived_side_to_at_least_one() {
+        // A source with a much taller-than-wide ratio can derive a 0 before clamping.
+        assert_eq!(compute_target_dimensions(1000, 1, Some(1), None), (1, 1));
+    }
+
+    #[test]
+    fn compute_target_dimensions_falls_back_to_source_when_neither_given() {
+        assert_eq!(compute_target_dimensions(400, 200, None, None), (400, 200));
+    }
+}